@@ -22,14 +22,18 @@ pub mod hg_connect;
 pub(crate) mod hg_connect_http;
 pub(crate) mod hg_connect_stdio;
 pub(crate) mod hg_data;
+pub(crate) mod store;
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ffi::CString;
 use std::ffi::OsString;
-use std::io::{stdout, Write};
+use std::fs::File;
+use std::io::{stdin, stdout, BufReader, Write};
 use std::iter::repeat;
 use std::mem;
+use std::path::PathBuf;
 use std::os::raw::c_char;
 use std::os::raw::c_int;
 use std::str::{self, FromStr};
@@ -42,6 +46,7 @@ use libcinnabar::{
     ensure_notes, files_meta, generate_manifest, git2hg, hg_object_id, resolve_hg, AbbrevHgObjectId,
 };
 use libgit::{get_note, object_id, repo_get_oid_committish, strbuf, the_repository, Object};
+use store::{BundleReader, BundleSpec, BundleWriter};
 use util::{FromBytes, OsStrExt, SliceExt};
 
 const HELPER_HASH: &str = env!("HELPER_HASH");
@@ -75,45 +80,141 @@ pub fn prepare_arg(arg: OsString) -> Vec<u16> {
     arg
 }
 
-fn do_hg2git(abbrev: Option<usize>, sha1s: Vec<AbbrevHgObjectId>) -> Result<(), String> {
+fn do_hg2git(
+    abbrev: Option<usize>,
+    batch: bool,
+    sha1s: Vec<AbbrevHgObjectId>,
+) -> Result<(), String> {
     let abbrev = abbrev.unwrap_or(40);
-    for sha1 in &sha1s {
+    let resolve = |sha1: &AbbrevHgObjectId| {
         let hex = format!("{}", sha1.to_git().unwrap_or_else(object_id::null));
         println!("{}", &hex[..abbrev]);
+    };
+    if batch {
+        for token in stdin_tokens() {
+            let token = token.map_err(|e| e.to_string())?;
+            match AbbrevHgObjectId::from_str(&token) {
+                Ok(sha1) => match sha1.to_git() {
+                    Some(git) => println!("{} {}", token, &format!("{}", git)[..abbrev]),
+                    None => println!("{} missing", token),
+                },
+                Err(_) => println!("{} missing", token),
+            }
+        }
+    } else {
+        for sha1 in &sha1s {
+            resolve(sha1);
+        }
     }
     Ok(())
 }
 
-fn do_git2hg(abbrev: Option<usize>, committish: Vec<OsString>) -> Result<(), String> {
+fn do_git2hg(
+    abbrev: Option<usize>,
+    batch: bool,
+    committish: Vec<OsString>,
+) -> Result<(), String> {
     let abbrev = abbrev.unwrap_or(40);
     unsafe {
         ensure_notes(&mut git2hg);
+    }
+    let resolve = |c: &[u8]| {
+        let mut oid = object_id::null();
+        let c = CString::new(c).unwrap();
+        if unsafe { repo_get_oid_committish(the_repository, c.as_ptr(), &mut oid) } == 0 {
+            oid.to_hg()
+        } else {
+            None
+        }
+    };
+    if batch {
+        for token in stdin_tokens() {
+            let token = token.map_err(|e| e.to_string())?;
+            match resolve(token.as_bytes()) {
+                Some(note) => println!("{} {}", token, &format!("{}", note)[..abbrev]),
+                None => println!("{} missing", token),
+            }
+        }
+    } else {
         for c in &committish {
-            let mut oid = object_id::null();
-            let c = CString::new(c.as_bytes()).unwrap();
-            let note = if repo_get_oid_committish(the_repository, c.as_ptr(), &mut oid) == 0 {
-                oid.to_hg()
-            } else {
-                None
-            };
-            let hex = format!("{}", note.unwrap_or_else(hg_object_id::null));
+            let hex = format!("{}", resolve(c.as_bytes()).unwrap_or_else(hg_object_id::null));
             println!("{}", &hex[..abbrev]);
         }
     }
     Ok(())
 }
 
+// Iterate over whitespace-delimited tokens read from stdin, reading one line
+// at a time so answers stream as input arrives, mirroring `git cat-file
+// --batch`.
+fn stdin_tokens() -> impl Iterator<Item = std::io::Result<String>> {
+    stdin().lines().flat_map(|line| match line {
+        Ok(line) => line
+            .split_whitespace()
+            .map(|t| Ok(t.to_owned()))
+            .collect::<Vec<_>>()
+            .into_iter(),
+        Err(e) => vec![Err(e)].into_iter(),
+    })
+}
+
 enum HgObjectType {
     Changeset,
     Manifest,
     File,
 }
 
-fn do_data(rev: AbbrevHgObjectId, typ: HgObjectType) -> Result<(), String> {
-    let git_obj = rev
-        .to_git()
-        .ok_or_else(|| format!("Unknown revision: {}", rev))?;
-    match typ {
+impl HgObjectType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HgObjectType::Changeset => "changeset",
+            HgObjectType::Manifest => "manifest",
+            HgObjectType::File => "file",
+        }
+    }
+}
+
+fn do_data(rev: AbbrevHgObjectId, typ: HgObjectType, verify: bool) -> Result<(), String> {
+    match reconstruct_data(&rev, &typ, verify)? {
+        Some(data) => stdout().write_all(&data).map_err(|e| e.to_string()),
+        None => Err(format!("Unknown revision: {}", rev)),
+    }
+}
+
+fn do_data_batch(typ: HgObjectType) -> Result<(), String> {
+    let mut stdout = stdout();
+    for token in stdin_tokens() {
+        let token = token.map_err(|e| e.to_string())?;
+        let rev = match AbbrevHgObjectId::from_str(&token) {
+            Ok(rev) => rev,
+            Err(_) => {
+                writeln!(stdout, "{} missing", token).map_err(|e| e.to_string())?;
+                continue;
+            }
+        };
+        match reconstruct_data(&rev, &typ, false)? {
+            Some(data) => {
+                writeln!(stdout, "{} {} {}", token, typ.as_str(), data.len())
+                    .map_err(|e| e.to_string())?;
+                stdout.write_all(&data).map_err(|e| e.to_string())?;
+                stdout.write_all(b"\n").map_err(|e| e.to_string())?;
+            }
+            None => writeln!(stdout, "{} missing", token).map_err(|e| e.to_string())?,
+        }
+    }
+    Ok(())
+}
+
+fn reconstruct_data(
+    rev: &AbbrevHgObjectId,
+    typ: &HgObjectType,
+    verify: bool,
+) -> Result<Option<Vec<u8>>, String> {
+    let git_obj = match rev.to_git() {
+        Some(git_obj) => git_obj,
+        None => return Ok(None),
+    };
+    Ok(Some(match typ {
         HgObjectType::Changeset => unsafe {
             ensure_notes(&mut git2hg);
             let note = get_note(&mut git2hg, &git_obj).as_ref().unwrap();
@@ -169,6 +270,10 @@ fn do_data(rev: AbbrevHgObjectId, typ: HgObjectType) -> Result<(), String> {
             changeset.extend_from_slice(&hg_utcoffset);
             if extra.is_some() || hg_committer.is_some() {
                 changeset.push(b' ');
+                // The committer is spliced into `extra` at its sorted position
+                // without otherwise reordering or rewriting the existing keys,
+                // so signature-bearing fields round-trip to the exact bytes the
+                // gpg extension signed.
                 let hg_committer = hg_committer.map(|c| {
                     let mut hg_committer = Vec::new();
                     hg_committer.extend_from_slice(b"committer:");
@@ -242,37 +347,776 @@ fn do_data(rev: AbbrevHgObjectId, typ: HgObjectType) -> Result<(), String> {
                 changeset = adjusted;
             }
             //TODO: adjustement, per end of ChangesetPatcher.apply
-            stdout().write_all(&changeset).map_err(|e| e.to_string())?;
+            if verify {
+                verify_changeset_signature(&node)?;
+            }
+            changeset.to_owned()
         },
         HgObjectType::Manifest => {
             let buf = unsafe { generate_manifest(&git_obj).as_ref().unwrap() };
-            stdout()
-                .write_all(buf.as_bytes())
-                .map_err(|e| e.to_string())?;
+            buf.as_bytes().to_owned()
         }
         HgObjectType::File => {
-            let mut stdout = stdout();
+            let mut out = Vec::new();
             unsafe {
                 ensure_notes(&mut files_meta);
-                resolve_hg(&mut files_meta, rev.as_hg_object_id(), rev.len())
+                if let Some(o) = resolve_hg(&mut files_meta, rev.as_hg_object_id(), rev.len())
                     .as_ref()
                     .and_then(Object::read)
-                    .map(|o| {
-                        stdout.write_all(b"\x01\n")?;
-                        stdout.write_all(o.blob().unwrap().as_bytes())?;
-                        stdout.write_all(b"\x01\n")
-                    })
-                    .transpose()
-                    .and_then(|_| {
-                        stdout.write_all(Object::read(&git_obj).unwrap().blob().unwrap().as_bytes())
-                    })
-                    .map_err(|e| e.to_string())?;
+                {
+                    out.extend_from_slice(b"\x01\n");
+                    out.extend_from_slice(o.blob().unwrap().as_bytes());
+                    out.extend_from_slice(b"\x01\n");
+                }
+                out.extend_from_slice(Object::read(&git_obj).unwrap().blob().unwrap().as_bytes());
             }
+            out
+        }
+    }))
+}
+
+fn do_bundle(
+    version: u8,
+    output: PathBuf,
+    revs: Vec<OsString>,
+) -> Result<(), String> {
+    let spec = match version {
+        1 => BundleSpec::V1,
+        2 => BundleSpec::V2,
+        v => return Err(format!("unsupported bundle version: {}", v)),
+    };
+    unsafe {
+        ensure_notes(&mut git2hg);
+        ensure_notes(&mut files_meta);
+    }
+    let mut changesets = Vec::new();
+    for rev in &revs {
+        let mut oid = object_id::null();
+        let c = CString::new(rev.as_bytes()).unwrap();
+        if unsafe { repo_get_oid_committish(the_repository, c.as_ptr(), &mut oid) } != 0 {
+            return Err(format!("Unknown revision: {}", rev.to_string_lossy()));
         }
+        let node = oid
+            .to_hg()
+            .ok_or_else(|| format!("Not a mercurial changeset: {}", rev.to_string_lossy()))?;
+        changesets.push((oid, node));
+    }
+    let file = File::create(&output).map_err(|e| e.to_string())?;
+    let mut writer = BundleWriter::new(spec, file).map_err(|e| e.to_string())?;
+    writer
+        .write_changegroup(changesets.iter().map(|(git, _)| *git))
+        .map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())
+}
+
+fn do_unbundle(input: PathBuf) -> Result<(), String> {
+    unsafe {
+        ensure_notes(&mut git2hg);
+        ensure_notes(&mut files_meta);
+    }
+    let file = File::open(&input).map_err(|e| e.to_string())?;
+    // Parse the bundle header and its changelog / manifest / filelog groups,
+    // routing each chunk to the right metadata graph.
+    let reader = BundleReader::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+    for entry in reader {
+        let (part, chunk) = entry.map_err(|e| e.to_string())?;
+        store::store_changegroup_chunk(&part, &chunk)?;
     }
     Ok(())
 }
 
+// A single manifest entry: the file node and its flags (`x`, `l`, or empty).
+struct ManifestEntry {
+    node: AbbrevHgObjectId,
+    flags: Vec<u8>,
+}
+
+// Reconstruct the manifest of a changeset and parse it into a path-keyed map.
+fn changeset_manifest(
+    rev: &AbbrevHgObjectId,
+) -> Result<Option<BTreeMap<Vec<u8>, ManifestEntry>>, String> {
+    let git_obj = match rev.to_git() {
+        Some(git_obj) => git_obj,
+        None => return Ok(None),
+    };
+    let manifest = unsafe {
+        ensure_notes(&mut git2hg);
+        let note = get_note(&mut git2hg, &git_obj).as_ref().unwrap();
+        let metadata = Object::read(note).unwrap();
+        let metadata = metadata.blob().unwrap().as_bytes();
+        let mut manifest = hg_object_id::null();
+        for line in metadata.lines() {
+            if let (b"manifest", m) = line.split2(b' ').unwrap() {
+                manifest = hg_object_id::from_bytes(m).unwrap();
+            }
+        }
+        manifest
+    };
+    let manifest = AbbrevHgObjectId::from_str(&format!("{}", manifest)).unwrap();
+    let manifest_git = manifest
+        .to_git()
+        .ok_or_else(|| format!("Missing manifest for changeset: {}", rev))?;
+    let buf = unsafe { generate_manifest(&manifest_git).as_ref().unwrap() };
+    let mut entries = BTreeMap::new();
+    for line in buf.as_bytes().lines() {
+        let (path, rest) = line.split2(b'\0').unwrap();
+        let (node, flags) = rest.split_at(40);
+        entries.insert(
+            path.to_owned(),
+            ManifestEntry {
+                node: AbbrevHgObjectId::from_bytes(node).unwrap(),
+                flags: flags.to_owned(),
+            },
+        );
+    }
+    Ok(Some(entries))
+}
+
+// The raw file content for a manifest entry, as stored in the git blob.
+fn file_content(entry: &ManifestEntry) -> Vec<u8> {
+    match entry.node.to_git() {
+        Some(git_obj) => Object::read(&git_obj)
+            .unwrap()
+            .blob()
+            .unwrap()
+            .as_bytes()
+            .to_owned(),
+        None => Vec::new(),
+    }
+}
+
+fn do_ls_remote(
+    heads: bool,
+    branches: bool,
+    bookmarks: bool,
+    pattern: Option<String>,
+    url: OsString,
+) -> Result<(), String> {
+    // With no selector, report everything the server offers.
+    let (heads, branches, bookmarks) = if heads || branches || bookmarks {
+        (heads, branches, bookmarks)
+    } else {
+        (true, true, true)
+    };
+
+    let mut conn = hg_connect::get_connection(&url)
+        .ok_or_else(|| format!("Failed to connect to {}", url.to_string_lossy()))?;
+
+    let matches = |name: &[u8]| match &pattern {
+        Some(pattern) => name.contains_str(pattern),
+        None => true,
+    };
+
+    let mut stdout = stdout();
+    let mut emit = |node: &[u8], name: &[u8]| -> std::io::Result<()> {
+        if matches(name) {
+            stdout.write_all(node)?;
+            stdout.write_all(b"\t")?;
+            stdout.write_all(name)?;
+            writeln!(stdout)?;
+        }
+        Ok(())
+    };
+
+    if heads {
+        // Anonymous heads have no ref name; their node is their identity, so
+        // filter and display on the node itself.
+        for head in conn.heads() {
+            let node = format!("{}", head);
+            emit(node.as_bytes(), node.as_bytes()).map_err(|e| e.to_string())?;
+        }
+    }
+    if branches {
+        for (name, tips) in conn.branchmap() {
+            for tip in tips {
+                emit(format!("{}", tip).as_bytes(), &name).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    if bookmarks {
+        for (name, node) in conn.bookmarks() {
+            emit(format!("{}", node).as_bytes(), &name).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+// The changelog fields of a single changeset, as reconstructed from its git
+// commit and `git2hg` note.
+struct ChangesetFields {
+    node: hg_object_id,
+    author: Vec<u8>,
+    timestamp: Vec<u8>,
+    utcoffset: Vec<u8>,
+    description: Vec<u8>,
+    parents: Vec<object_id>,
+}
+
+// A frontier entry ordered by changeset date (newest first), with the git
+// commit id as a stable tie-breaker.
+struct LogEntry {
+    timestamp: i64,
+    key: String,
+    fields: ChangesetFields,
+}
+
+impl LogEntry {
+    fn new(key: String, fields: ChangesetFields) -> Self {
+        let timestamp = str::from_utf8(&fields.timestamp)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0);
+        LogEntry {
+            timestamp,
+            key,
+            fields,
+        }
+    }
+}
+
+impl PartialEq for LogEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.timestamp == other.timestamp && self.key == other.key
+    }
+}
+
+impl Eq for LogEntry {}
+
+impl Ord for LogEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.timestamp
+            .cmp(&other.timestamp)
+            .then_with(|| self.key.cmp(&other.key))
+    }
+}
+
+impl PartialOrd for LogEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn changeset_fields(git_obj: &object_id) -> ChangesetFields {
+    unsafe {
+        ensure_notes(&mut git2hg);
+        let note = get_note(&mut git2hg, git_obj).as_ref().unwrap();
+        let metadata = Object::read(note).unwrap();
+        let metadata = metadata.blob().unwrap().as_bytes();
+        let commit = Object::read(git_obj).unwrap();
+        let commit = commit.commit().unwrap();
+        let commit = commit.as_bytes();
+        let (header, body) = commit.split2(&b"\n\n"[..]).unwrap();
+        let mut parents = Vec::new();
+        let mut author = None;
+        for line in header.lines() {
+            if line.is_empty() {
+                break;
+            }
+            match line.split2(b' ').unwrap() {
+                (b"parent", p) => parents.push(object_id::from_bytes(p).unwrap()),
+                (b"author", a) => author = Some(a),
+                _ => {}
+            }
+        }
+        let (mut hg_author, hg_timestamp, hg_utcoffset) =
+            Authorship::from_git_bytes(author.unwrap()).to_hg_parts();
+        let mut node = hg_object_id::null();
+        for line in metadata.lines() {
+            match line.split2(b' ').unwrap() {
+                (b"changeset", c) => node = hg_object_id::from_bytes(c).unwrap(),
+                (b"author", a) => hg_author = a.to_owned(),
+                _ => {}
+            }
+        }
+        ChangesetFields {
+            node,
+            author: hg_author,
+            timestamp: hg_timestamp,
+            utcoffset: hg_utcoffset,
+            description: body.to_owned(),
+            parents,
+        }
+    }
+}
+
+fn do_log(
+    limit: Option<usize>,
+    template: Option<String>,
+    reverse: bool,
+    revs: Vec<AbbrevHgObjectId>,
+) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    // A date-ordered frontier, so the changelog is emitted newest-first like
+    // `hg log`, merging all heads' ancestries instead of draining one head's
+    // DFS prefix first.
+    let mut frontier = std::collections::BinaryHeap::new();
+    let mut enqueue = |frontier: &mut std::collections::BinaryHeap<LogEntry>,
+                       seen: &mut std::collections::HashSet<String>,
+                       git_obj: object_id| {
+        let key = format!("{}", git_obj);
+        if seen.insert(key.clone()) {
+            frontier.push(LogEntry::new(key, changeset_fields(&git_obj)));
+        }
+    };
+
+    if revs.is_empty() {
+        for head in store::changeset_heads() {
+            enqueue(&mut frontier, &mut seen, head);
+        }
+    } else {
+        for rev in &revs {
+            let git_obj = rev
+                .to_git()
+                .ok_or_else(|| format!("Unknown revision: {}", rev))?;
+            enqueue(&mut frontier, &mut seen, git_obj);
+        }
+    }
+
+    // Pop the most recent changeset, then fold its parents into the frontier,
+    // stopping once `limit` changesets have been collected.
+    let mut order = Vec::new();
+    while let Some(entry) = frontier.pop() {
+        if limit.map_or(false, |n| order.len() >= n) {
+            break;
+        }
+        let parents = entry.fields.parents.clone();
+        order.push(entry.fields);
+        for parent in parents {
+            enqueue(&mut frontier, &mut seen, parent);
+        }
+    }
+
+    if reverse {
+        order.reverse();
+    }
+
+    let mut stdout = stdout();
+    for fields in &order {
+        render_changeset(&mut stdout, fields, template.as_deref()).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Render a single changeset, either through a `{field}` template or the
+// default multi-line block.
+fn render_changeset(
+    out: &mut impl Write,
+    fields: &ChangesetFields,
+    template: Option<&str>,
+) -> std::io::Result<()> {
+    if let Some(template) = template {
+        let mut rendered = template
+            .replace("{node}", &format!("{}", fields.node))
+            .replace("{author}", &String::from_utf8_lossy(&fields.author))
+            .replace(
+                "{date}",
+                &format!(
+                    "{} {}",
+                    String::from_utf8_lossy(&fields.timestamp),
+                    String::from_utf8_lossy(&fields.utcoffset)
+                ),
+            )
+            .replace("{desc}", &String::from_utf8_lossy(&fields.description));
+        rendered = rendered.replace("\\n", "\n");
+        out.write_all(rendered.as_bytes())?;
+        out.write_all(b"\n")
+    } else {
+        writeln!(out, "changeset:   {}", fields.node)?;
+        write!(out, "user:        ")?;
+        out.write_all(&fields.author)?;
+        writeln!(out)?;
+        write!(out, "date:        ")?;
+        out.write_all(&fields.timestamp)?;
+        out.write_all(b" ")?;
+        out.write_all(&fields.utcoffset)?;
+        writeln!(out)?;
+        write!(out, "summary:     ")?;
+        out.write_all(fields.description.lines().next().unwrap_or(b""))?;
+        writeln!(out)?;
+        writeln!(out)
+    }
+}
+
+// Validate the gpg signature(s) recorded for a changeset by Mercurial's gpg
+// extension. A changeset's signature is never stored in that changeset's own
+// manifest — the signed payload is the node hash, which only exists once the
+// changeset has been created, so the `sig` entry is committed in a later
+// changeset's `.hgsigs`. We therefore collect `.hgsigs` from the changelog
+// heads (the accumulated file carries every historical signature) and match
+// on `node`. Each entry is `<node> <version> <base64-sig>`; the signed
+// payload for version 0 is the node's hex representation followed by a newline
+// (`node2txt`). The signer reported by gpg is echoed on stderr, and a failing
+// verification turns into an error so the command exits nonzero.
+fn verify_changeset_signature(node: &hg_object_id) -> Result<(), String> {
+    let node_hex = format!("{}", node);
+    // `.hgsigs` is cumulative, so the same signature line appears in every
+    // descendant head; stop at the first head that carries it.
+    let mut found = false;
+    for head in store::changeset_heads() {
+        let head = AbbrevHgObjectId::from_str(&format!("{}", head.to_hg().unwrap())).unwrap();
+        let manifest = match changeset_manifest(&head)? {
+            Some(manifest) => manifest,
+            None => continue,
+        };
+        let sigs = match manifest.get(&b".hgsigs"[..].to_vec()) {
+            Some(entry) => file_content(entry),
+            None => continue,
+        };
+        for line in sigs.lines() {
+            let (signed, rest) = match line.split2(b' ') {
+                Some(parts) => parts,
+                None => continue,
+            };
+            if signed != node_hex.as_bytes() {
+                continue;
+            }
+            found = true;
+            let (_version, sig) = rest.split2(b' ').unwrap_or((b"0", rest));
+            let sig =
+                base64_decode(sig).ok_or_else(|| format!("Malformed signature for {}", node))?;
+            let payload = format!("{}\n", node_hex);
+            let signer = gpg_verify(payload.as_bytes(), &sig)
+                .map_err(|e| format!("Signature verification failed for {}: {}", node, e))?;
+            eprintln!("{} signed by {}", node, signer);
+        }
+        if found {
+            break;
+        }
+    }
+    if !found {
+        eprintln!("No signature found for {}", node);
+    }
+    Ok(())
+}
+
+// Run `gpg` over a detached signature and return the reported signer on
+// success, or an error describing the failure. The signature is written to a
+// process- and node-unique temp file that is removed afterwards; the signed
+// payload is fed to gpg on stdin so it never touches disk.
+fn gpg_verify(payload: &[u8], sig: &[u8]) -> Result<String, String> {
+    use std::process::{Command, Stdio};
+    let sig_path = std::env::temp_dir().join(format!(
+        "cinnabar-hgsig-{}-{}.sig",
+        std::process::id(),
+        payload.trim().as_bstr()
+    ));
+    File::create(&sig_path)
+        .and_then(|mut f| f.write_all(sig))
+        .map_err(|e| e.to_string())?;
+    let result = (|| {
+        let mut child = Command::new("gpg")
+            .arg("--status-fd=1")
+            .arg("--verify")
+            .arg(&sig_path)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(payload)
+            .map_err(|e| e.to_string())?;
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+        let status = String::from_utf8_lossy(&output.stdout);
+        let signer = status
+            .lines()
+            .find_map(|line| line.strip_prefix("[GNUPG:] GOODSIG "))
+            .and_then(|rest| rest.splitn(2, ' ').nth(1))
+            .map(|s| s.to_owned());
+        match signer {
+            Some(signer) if output.status.success() => Ok(signer),
+            _ => Err("bad signature".to_string()),
+        }
+    })();
+    let _ = std::fs::remove_file(&sig_path);
+    result
+}
+
+// Decode standard (non-URL) base64, ignoring embedded whitespace.
+fn base64_decode(input: &[u8]) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    let mut out = Vec::new();
+    for &c in input {
+        if c == b'=' || c.is_ascii_whitespace() {
+            continue;
+        }
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = (bits << 6) | value;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            out.push((bits >> nbits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn do_diff(
+    stat: bool,
+    git: bool,
+    rev1: AbbrevHgObjectId,
+    rev2: Option<AbbrevHgObjectId>,
+) -> Result<(), String> {
+    // Default the base to the first parent of `rev1`.
+    let (base, target) = match rev2 {
+        Some(rev2) => (Some(rev1), rev2),
+        None => {
+            let git_obj = rev1
+                .to_git()
+                .ok_or_else(|| format!("Unknown revision: {}", rev1))?;
+            let commit = Object::read(&git_obj).unwrap();
+            let commit = commit.commit().unwrap();
+            let commit = commit.as_bytes();
+            let (header, _) = commit.split2(&b"\n\n"[..]).unwrap();
+            let parent = header.lines().find_map(|line| match line.split2(b' ') {
+                Some((b"parent", p)) => object_id::from_bytes(p).ok().and_then(|p| p.to_hg()),
+                _ => None,
+            });
+            let base = parent
+                .map(|p| AbbrevHgObjectId::from_str(&format!("{}", p)).unwrap());
+            (base, rev1)
+        }
+    };
+
+    let from = match base {
+        Some(base) => changeset_manifest(&base)?
+            .ok_or_else(|| format!("Unknown revision: {}", base))?,
+        None => BTreeMap::new(),
+    };
+    let to =
+        changeset_manifest(&target)?.ok_or_else(|| format!("Unknown revision: {}", target))?;
+
+    let mut paths = from.keys().chain(to.keys()).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+
+    let mut stdout = stdout();
+    for path in paths {
+        let old = from.get(path);
+        let new = to.get(path);
+        match (old, new) {
+            (Some(a), Some(b)) if a.node == b.node && a.flags == b.flags => continue,
+            _ => {}
+        }
+        if stat {
+            let old_content = old.map(file_content).unwrap_or_default();
+            let new_content = new.map(file_content).unwrap_or_default();
+            let (added, removed) = diff_stat(&old_content, &new_content);
+            writeln!(
+                stdout,
+                " {} | {} {}{}",
+                path.as_bstr(),
+                added + removed,
+                "+".repeat(added),
+                "-".repeat(removed)
+            )
+            .map_err(|e| e.to_string())?;
+            continue;
+        }
+        let old_content = old.map(file_content).unwrap_or_default();
+        let new_content = new.map(file_content).unwrap_or_default();
+        if git {
+            write_git_headers(&mut stdout, path, old, new).map_err(|e| e.to_string())?;
+        } else {
+            writeln!(
+                stdout,
+                "diff -r {} -r {} {}",
+                base.map(|b| format!("{}", b)).unwrap_or_default(),
+                target,
+                path.as_bstr()
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        write_file_headers(&mut stdout, path, old.is_some(), new.is_some())
+            .map_err(|e| e.to_string())?;
+        write_unified(&mut stdout, &old_content, &new_content).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Count added/removed lines between two blobs.
+fn diff_stat(old: &[u8], new: &[u8]) -> (usize, usize) {
+    let old_lines: Vec<&[u8]> = old.lines().collect();
+    let new_lines: Vec<&[u8]> = new.lines().collect();
+    let common = lcs(&old_lines, &new_lines);
+    (new_lines.len() - common, old_lines.len() - common)
+}
+
+fn write_git_headers(
+    out: &mut impl Write,
+    path: &[u8],
+    old: Option<&ManifestEntry>,
+    new: Option<&ManifestEntry>,
+) -> std::io::Result<()> {
+    writeln!(out, "diff --git a/{} b/{}", path.as_bstr(), path.as_bstr())?;
+    match (old, new) {
+        (None, Some(_)) => writeln!(out, "new file mode 100644")?,
+        (Some(_), None) => writeln!(out, "deleted file mode 100644")?,
+        _ => {}
+    }
+    Ok(())
+}
+
+// Emit the `---`/`+++` file markers, using `/dev/null` for the missing side of
+// an added or deleted file.
+fn write_file_headers(
+    out: &mut impl Write,
+    path: &[u8],
+    old_present: bool,
+    new_present: bool,
+) -> std::io::Result<()> {
+    if old_present {
+        writeln!(out, "--- a/{}", path.as_bstr())?;
+    } else {
+        writeln!(out, "--- /dev/null")?;
+    }
+    if new_present {
+        writeln!(out, "+++ b/{}", path.as_bstr())
+    } else {
+        writeln!(out, "+++ /dev/null")
+    }
+}
+
+// Emit a unified diff with three lines of context, Mercurial style.
+fn write_unified(out: &mut impl Write, old: &[u8], new: &[u8]) -> std::io::Result<()> {
+    let old_lines: Vec<&[u8]> = old.lines().collect();
+    let new_lines: Vec<&[u8]> = new.lines().collect();
+    for line in unified_hunks(&old_lines, &new_lines, 3) {
+        out.write_all(&line)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Longest common subsequence length over lines.
+fn lcs(a: &[&[u8]], b: &[&[u8]]) -> usize {
+    let mut table = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i][j] = if a[i] == b[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+    table[0][0]
+}
+
+// Produce unified-diff hunk lines (with `@@` headers) from the full edit
+// script, starting a new hunk where changes are separated by more than twice
+// `context` common lines.
+fn unified_hunks(a: &[&[u8]], b: &[&[u8]], context: usize) -> Vec<Vec<u8>> {
+    // Build the full edit script via backtracking over the LCS table.
+    let mut table = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i][j] = if a[i] == b[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+    enum Edit {
+        Keep(usize, usize),
+        Del(usize),
+        Ins(usize),
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut script = Vec::new();
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            script.push(Edit::Keep(i, j));
+            i += 1;
+            j += 1;
+        } else if table[i + 1][j] >= table[i][j + 1] {
+            script.push(Edit::Del(i));
+            i += 1;
+        } else {
+            script.push(Edit::Ins(j));
+            j += 1;
+        }
+    }
+    while i < a.len() {
+        script.push(Edit::Del(i));
+        i += 1;
+    }
+    while j < b.len() {
+        script.push(Edit::Ins(j));
+        j += 1;
+    }
+
+    // Split into hunks around changed regions, keeping `context` kept lines.
+    let changed: Vec<usize> = script
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Keep(..)))
+        .map(|(idx, _)| idx)
+        .collect();
+    let mut out = Vec::new();
+    if changed.is_empty() {
+        return out;
+    }
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for &idx in &changed {
+        let lo = idx.saturating_sub(context);
+        let hi = (idx + context + 1).min(script.len());
+        match groups.last_mut() {
+            Some(last) if lo <= last.1 => last.1 = last.1.max(hi),
+            _ => groups.push((lo, hi)),
+        }
+    }
+    for (lo, hi) in groups {
+        let (mut old_start, mut new_start) = (None, None);
+        let (mut old_len, mut new_len) = (0, 0);
+        let mut body = Vec::new();
+        for e in &script[lo..hi] {
+            match e {
+                Edit::Keep(ai, bj) => {
+                    old_start.get_or_insert(*ai);
+                    new_start.get_or_insert(*bj);
+                    old_len += 1;
+                    new_len += 1;
+                    let mut line = vec![b' '];
+                    line.extend_from_slice(a[*ai]);
+                    body.push(line);
+                }
+                Edit::Del(ai) => {
+                    old_start.get_or_insert(*ai);
+                    old_len += 1;
+                    let mut line = vec![b'-'];
+                    line.extend_from_slice(a[*ai]);
+                    body.push(line);
+                }
+                Edit::Ins(bj) => {
+                    new_start.get_or_insert(*bj);
+                    new_len += 1;
+                    let mut line = vec![b'+'];
+                    line.extend_from_slice(b[*bj]);
+                    body.push(line);
+                }
+            }
+        }
+        let header = format!(
+            "@@ -{},{} +{},{} @@",
+            old_start.map(|s| s + 1).unwrap_or(0),
+            old_len,
+            new_start.map(|s| s + 1).unwrap_or(0),
+            new_len
+        );
+        out.push(header.into_bytes());
+        out.extend(body);
+    }
+    out
+}
+
 #[derive(Debug)]
 struct AbbrevSize(usize);
 
@@ -309,9 +1153,89 @@ enum CinnabarCommand {
         #[structopt(conflicts_with = "changeset")]
         #[structopt(help = "Open manifest")]
         manifest: bool,
-        #[structopt(required = true)]
+        #[structopt(long)]
+        #[structopt(conflicts_with = "rev")]
+        #[structopt(help = "Read object names from stdin")]
+        batch: bool,
+        #[structopt(long)]
+        #[structopt(requires = "changeset")]
+        #[structopt(help = "Verify the changeset's gpg signature")]
+        verify: bool,
+        #[structopt(required_unless = "batch")]
         #[structopt(help = "Revision")]
-        rev: AbbrevHgObjectId,
+        rev: Option<AbbrevHgObjectId>,
+    },
+    #[structopt(name = "bundle")]
+    #[structopt(about = "Create a mercurial bundle")]
+    Bundle {
+        #[structopt(long)]
+        #[structopt(default_value = "2")]
+        #[structopt(help = "Bundle version (1 for HG10, 2 for HG20)")]
+        version: u8,
+        #[structopt(short = "o")]
+        #[structopt(parse(from_os_str))]
+        #[structopt(help = "Output file")]
+        output: PathBuf,
+        #[structopt(required = true)]
+        #[structopt(parse(from_os_str))]
+        #[structopt(help = "Git sha1/committish")]
+        revs: Vec<OsString>,
+    },
+    #[structopt(name = "unbundle")]
+    #[structopt(about = "Ingest a mercurial bundle into the local metadata")]
+    Unbundle {
+        #[structopt(parse(from_os_str))]
+        #[structopt(help = "Bundle file")]
+        input: PathBuf,
+    },
+    #[structopt(name = "ls-remote")]
+    #[structopt(about = "List references in a mercurial remote")]
+    LsRemote {
+        #[structopt(long)]
+        #[structopt(help = "Show remote heads")]
+        heads: bool,
+        #[structopt(long)]
+        #[structopt(help = "Show branch tips")]
+        branches: bool,
+        #[structopt(long)]
+        #[structopt(help = "Show bookmarks")]
+        bookmarks: bool,
+        #[structopt(required = true)]
+        #[structopt(parse(from_os_str))]
+        #[structopt(help = "Mercurial URL")]
+        url: OsString,
+        #[structopt(help = "Only show references matching the pattern")]
+        pattern: Option<String>,
+    },
+    #[structopt(name = "log")]
+    #[structopt(about = "Show the mercurial changelog")]
+    Log {
+        #[structopt(long, short = "n")]
+        #[structopt(help = "Limit the number of changesets shown")]
+        limit: Option<usize>,
+        #[structopt(long, visible_alias = "format")]
+        #[structopt(help = "Template with {node}, {author}, {date}, {desc} fields")]
+        template: Option<String>,
+        #[structopt(long)]
+        #[structopt(help = "Show changesets in reverse order")]
+        reverse: bool,
+        #[structopt(help = "Changeset (defaults to all heads)")]
+        rev: Vec<AbbrevHgObjectId>,
+    },
+    #[structopt(name = "diff")]
+    #[structopt(about = "Show changes between two mercurial revisions")]
+    Diff {
+        #[structopt(long)]
+        #[structopt(help = "Output a diffstat summary")]
+        stat: bool,
+        #[structopt(long)]
+        #[structopt(help = "Use git-style extended diff headers")]
+        git: bool,
+        #[structopt(required = true)]
+        #[structopt(help = "Revision (diffed against its first parent when alone)")]
+        rev1: AbbrevHgObjectId,
+        #[structopt(help = "Revision to diff to, with rev1 as the base")]
+        rev2: Option<AbbrevHgObjectId>,
     },
     #[structopt(name = "hg2git")]
     #[structopt(about = "Convert mercurial sha1 to corresponding git sha1")]
@@ -321,7 +1245,11 @@ enum CinnabarCommand {
         #[structopt(max_values = 1)]
         #[structopt(help = "Show a partial prefix")]
         abbrev: Option<Vec<AbbrevSize>>,
-        #[structopt(required = true)]
+        #[structopt(long)]
+        #[structopt(conflicts_with = "sha1")]
+        #[structopt(help = "Read sha1s from stdin")]
+        batch: bool,
+        #[structopt(required_unless = "batch")]
         #[structopt(help = "Mercurial sha1")]
         sha1: Vec<AbbrevHgObjectId>,
     },
@@ -333,7 +1261,11 @@ enum CinnabarCommand {
         #[structopt(max_values = 1)]
         #[structopt(help = "Show a partial prefix")]
         abbrev: Option<Vec<AbbrevSize>>,
-        #[structopt(required = true)]
+        #[structopt(long)]
+        #[structopt(conflicts_with = "committish")]
+        #[structopt(help = "Read committishes from stdin")]
+        batch: bool,
+        #[structopt(required_unless = "batch")]
         #[structopt(help = "Git sha1/committish")]
         #[structopt(parse(from_os_str))]
         committish: Vec<OsString>,
@@ -370,21 +1302,63 @@ fn git_cinnabar(argv0: *const c_char) -> i32 {
         Data {
             changeset,
             manifest,
+            batch,
+            verify,
             rev,
-        } => do_data(
-            rev,
-            match (changeset, manifest) {
+        } => {
+            let typ = match (changeset, manifest) {
                 (true, false) => HgObjectType::Changeset,
                 (false, true) => HgObjectType::Manifest,
                 (false, false) => HgObjectType::File,
                 (true, true) => unreachable!(),
-            },
-        ),
-        Hg2Git { abbrev, sha1 } => {
-            do_hg2git(abbrev.map(|v| v.get(0).map(|a| a.0).unwrap_or(12)), sha1)
+            };
+            if batch {
+                do_data_batch(typ)
+            } else {
+                do_data(rev.unwrap(), typ, verify)
+            }
         }
-        Git2Hg { abbrev, committish } => do_git2hg(
+        Bundle {
+            version,
+            output,
+            revs,
+        } => do_bundle(version, output, revs),
+        Unbundle { input } => do_unbundle(input),
+        LsRemote {
+            heads,
+            branches,
+            bookmarks,
+            url,
+            pattern,
+        } => do_ls_remote(heads, branches, bookmarks, pattern, url),
+        Log {
+            limit,
+            template,
+            reverse,
+            rev,
+        } => do_log(limit, template, reverse, rev),
+        Diff {
+            stat,
+            git,
+            rev1,
+            rev2,
+        } => do_diff(stat, git, rev1, rev2),
+        Hg2Git {
+            abbrev,
+            batch,
+            sha1,
+        } => do_hg2git(
+            abbrev.map(|v| v.get(0).map(|a| a.0).unwrap_or(12)),
+            batch,
+            sha1,
+        ),
+        Git2Hg {
+            abbrev,
+            batch,
+            committish,
+        } => do_git2hg(
             abbrev.map(|v| v.get(0).map(|a| a.0).unwrap_or(12)),
+            batch,
             committish,
         ),
     };
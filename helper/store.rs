@@ -0,0 +1,404 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Mercurial bundle plumbing used by the `bundle`/`unbundle` subcommands
+//! (changegroup chunk iteration and storage, bundle writing), and changeset
+//! head enumeration used by `log` and `data --verify`.
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::libgit::{object_id, strbuf};
+
+/// Bundle container version: `HG10` (a bare, optionally-compressed
+/// changegroup) or `HG20` (bundle2, a framed sequence of parts).
+pub enum BundleSpec {
+    V1,
+    V2,
+}
+
+impl BundleSpec {
+    fn magic(&self) -> &'static [u8] {
+        match self {
+            BundleSpec::V1 => b"HG10UN",
+            BundleSpec::V2 => b"HG20",
+        }
+    }
+}
+
+/// Writes a standalone changegroup bundle covering a set of changesets,
+/// emitting the changelog, manifest and filelog groups in turn.
+pub struct BundleWriter<W: Write> {
+    spec: BundleSpec,
+    writer: W,
+}
+
+impl<W: Write> BundleWriter<W> {
+    pub fn new(spec: BundleSpec, mut writer: W) -> io::Result<Self> {
+        writer.write_all(spec.magic())?;
+        if let BundleSpec::V2 = spec {
+            // Empty bundle2 stream parameters.
+            writer.write_all(&0u32.to_be_bytes())?;
+        }
+        Ok(BundleWriter { spec, writer })
+    }
+
+    /// Emit the changegroup for the given changeset git objects, walking the
+    /// changelog, manifest and filelog graphs each as a delta group. For
+    /// `HG20` the changegroup is wrapped in a bundle2 `changegroup` part so
+    /// the result is a valid bundle `hg unbundle` can read.
+    pub fn write_changegroup(
+        &mut self,
+        changesets: impl Iterator<Item = object_id>,
+    ) -> io::Result<()> {
+        let changesets = changesets.collect::<Vec<_>>();
+        let changegroup = build_changegroup(&changesets)?;
+        match self.spec {
+            BundleSpec::V1 => self.writer.write_all(&changegroup),
+            BundleSpec::V2 => {
+                // bundle2 part header for an advisory `changegroup` part with
+                // no parameters.
+                let mut header = Vec::new();
+                header.push(b"changegroup".len() as u8);
+                header.extend_from_slice(b"changegroup");
+                header.extend_from_slice(&0u32.to_be_bytes()); // part id
+                header.push(0); // mandatory param count
+                header.push(0); // advisory param count
+                self.writer
+                    .write_all(&(header.len() as u32).to_be_bytes())?;
+                self.writer.write_all(&header)?;
+                // Part payload, delivered as a single chunk (plain length,
+                // unlike changegroup-internal framing) then terminated.
+                self.writer
+                    .write_all(&(changegroup.len() as u32).to_be_bytes())?;
+                self.writer.write_all(&changegroup)?;
+                write_terminator(&mut self.writer)
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let BundleSpec::V2 = self.spec {
+            // bundle2 end-of-parts marker.
+            self.writer.write_all(&0u32.to_be_bytes())?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// A single delta entry within a changegroup.
+pub struct RevChunk {
+    pub node: object_id,
+    pub parent1: object_id,
+    pub parent2: object_id,
+    pub delta_base: object_id,
+    pub data: Vec<u8>,
+}
+
+/// Which graph a changegroup group belongs to, so `unbundle` can route each
+/// chunk to the right metadata notes tree.
+pub enum ChangegroupPart {
+    Changelog,
+    Manifest,
+    File(Vec<u8>),
+}
+
+/// Parses a bundle's header and the sequence of changelog / manifest /
+/// filelog groups it contains, yielding each chunk tagged with its group.
+pub struct BundleReader {
+    /// The raw changegroup stream, whether read directly (HG10) or unwrapped
+    /// from the bundle2 `changegroup` part (HG20).
+    reader: Box<dyn BufRead>,
+    part: ChangegroupPart,
+    /// `true` once the changelog and manifest groups have been consumed and
+    /// we are iterating the per-file groups.
+    in_files: bool,
+    finished: bool,
+}
+
+impl BundleReader {
+    pub fn new<R: BufRead + 'static>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let changegroup: Box<dyn BufRead> = match &magic {
+            b"HG10" => {
+                // Compression algorithm; only the uncompressed form is
+                // handled here. The rest of the stream is the changegroup.
+                let mut comp = [0u8; 2];
+                reader.read_exact(&mut comp)?;
+                if &comp != b"UN" {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unsupported bundle compression",
+                    ));
+                }
+                Box::new(reader)
+            }
+            b"HG20" => {
+                // Skip the bundle2 stream parameters, then locate the
+                // `changegroup` part and unwrap its chunked payload.
+                let mut params_len = [0u8; 4];
+                reader.read_exact(&mut params_len)?;
+                let mut params = vec![0u8; u32::from_be_bytes(params_len) as usize];
+                reader.read_exact(&mut params)?;
+                Box::new(io::Cursor::new(read_bundle2_changegroup(&mut reader)?))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a mercurial bundle",
+                ))
+            }
+        };
+        Ok(BundleReader {
+            reader: changegroup,
+            part: ChangegroupPart::Changelog,
+            in_files: false,
+            finished: false,
+        })
+    }
+
+    /// Read the next chunk, advancing across group boundaries. Returns the
+    /// chunk together with the group it belongs to.
+    fn read(&mut self) -> io::Result<Option<(ChangegroupPart, RevChunk)>> {
+        loop {
+            let mut len = [0u8; 4];
+            self.reader.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                // End of the current group; advance to the next.
+                match &self.part {
+                    ChangegroupPart::Changelog => self.part = ChangegroupPart::Manifest,
+                    ChangegroupPart::Manifest => self.in_files = true,
+                    ChangegroupPart::File(_) => {}
+                }
+                if self.in_files {
+                    // A filelog group is introduced by a chunk naming the
+                    // file; an empty such chunk ends the changegroup.
+                    match self.read_file_header()? {
+                        Some(name) => self.part = ChangegroupPart::File(name),
+                        None => {
+                            self.finished = true;
+                            return Ok(None);
+                        }
+                    }
+                }
+                continue;
+            }
+            let mut buf = vec![0u8; len - 4];
+            self.reader.read_exact(&mut buf)?;
+            let (heads, data) = buf.split_at(80);
+            let chunk = RevChunk {
+                node: object_id::from_raw_bytes(&heads[0..20]).unwrap(),
+                parent1: object_id::from_raw_bytes(&heads[20..40]).unwrap(),
+                parent2: object_id::from_raw_bytes(&heads[40..60]).unwrap(),
+                delta_base: object_id::from_raw_bytes(&heads[60..80]).unwrap(),
+                data: data.to_owned(),
+            };
+            let part = match &self.part {
+                ChangegroupPart::Changelog => ChangegroupPart::Changelog,
+                ChangegroupPart::Manifest => ChangegroupPart::Manifest,
+                ChangegroupPart::File(name) => ChangegroupPart::File(name.clone()),
+            };
+            return Ok(Some((part, chunk)));
+        }
+    }
+
+    fn read_file_header(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        let mut name = vec![0u8; len - 4];
+        self.reader.read_exact(&mut name)?;
+        Ok(Some(name))
+    }
+}
+
+impl Iterator for BundleReader {
+    type Item = io::Result<(ChangegroupPart, RevChunk)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        self.read().transpose()
+    }
+}
+
+// Walk a bundle2 stream's parts, returning the concatenated payload of the
+// first `changegroup` part. Other parts are skipped; the end-of-parts marker
+// (a zero-length part header) ends the search.
+fn read_bundle2_changegroup(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    loop {
+        let mut header_len = [0u8; 4];
+        reader.read_exact(&mut header_len)?;
+        let header_len = u32::from_be_bytes(header_len) as usize;
+        if header_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no changegroup part in bundle2",
+            ));
+        }
+        let mut header = vec![0u8; header_len];
+        reader.read_exact(&mut header)?;
+        let type_len = header[0] as usize;
+        let part_type = &header[1..1 + type_len];
+        // Read the chunked part payload, stopping at its zero-length chunk.
+        let mut payload = Vec::new();
+        loop {
+            let mut chunk_len = [0u8; 4];
+            reader.read_exact(&mut chunk_len)?;
+            let chunk_len = u32::from_be_bytes(chunk_len) as usize;
+            if chunk_len == 0 {
+                break;
+            }
+            let mut chunk = vec![0u8; chunk_len];
+            reader.read_exact(&mut chunk)?;
+            payload.extend_from_slice(&chunk);
+        }
+        if part_type == b"changegroup" {
+            return Ok(payload);
+        }
+    }
+}
+
+/// Store a single changegroup chunk into the metadata, routing it to the
+/// `git2hg`, `hg2git` or `files_meta` notes tree according to its group.
+pub fn store_changegroup_chunk(part: &ChangegroupPart, chunk: &RevChunk) -> Result<(), String> {
+    let kind = match part {
+        ChangegroupPart::Changelog => KIND_CHANGELOG,
+        ChangegroupPart::Manifest => KIND_MANIFEST,
+        ChangegroupPart::File(_) => KIND_FILE,
+    };
+    unsafe { store_chunk(kind, chunk) }
+}
+
+extern "C" {
+    // Serialize the delta group of `kind` for the given changesets (scoped to
+    // `path` for filelog groups) into `out`, terminated by its zero-length
+    // chunk.
+    fn dump_changegroup(
+        kind: u32,
+        nodes: *const object_id,
+        count: usize,
+        path: *const u8,
+        path_len: usize,
+        out: *mut strbuf,
+    );
+
+    // The set of file paths touched by the given changesets.
+    fn changed_file_paths(nodes: *const object_id, count: usize, out: *mut strbuf);
+
+    fn store_hg_changeset_chunk(
+        kind: u32,
+        node: *const object_id,
+        parent1: *const object_id,
+        parent2: *const object_id,
+        delta_base: *const object_id,
+        data: *const u8,
+        len: usize,
+    );
+}
+
+// The changegroup kinds, as understood by the C metadata store.
+const KIND_CHANGELOG: u32 = 0;
+const KIND_MANIFEST: u32 = 1;
+const KIND_FILE: u32 = 2;
+
+unsafe fn store_chunk(kind: u32, chunk: &RevChunk) -> Result<(), String> {
+    store_hg_changeset_chunk(
+        kind,
+        &chunk.node,
+        &chunk.parent1,
+        &chunk.parent2,
+        &chunk.delta_base,
+        chunk.data.as_ptr(),
+        chunk.data.len(),
+    );
+    Ok(())
+}
+
+// Serialize the full changegroup (changelog, manifest and per-file groups)
+// for the given changesets into a buffer.
+fn build_changegroup(changesets: &[object_id]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    write_group(&mut buf, KIND_CHANGELOG, changesets, b"")?;
+    write_group(&mut buf, KIND_MANIFEST, changesets, b"")?;
+    for file in changed_files(changesets) {
+        // Each filelog group is introduced by a chunk naming the file.
+        write_chunk(&mut buf, &file)?;
+        write_group(&mut buf, KIND_FILE, changesets, &file)?;
+    }
+    // Empty file-header chunk marks the end of the changegroup.
+    write_terminator(&mut buf)?;
+    Ok(buf)
+}
+
+// Frame a single changegroup chunk: a 4-byte big-endian total length followed
+// by the payload.
+fn write_chunk<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&((data.len() + 4) as u32).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+// The zero-length chunk terminating a group.
+fn write_terminator<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(&0u32.to_be_bytes())
+}
+
+// Emit a full delta group (changelog, manifest or a single filelog) followed
+// by its terminator.
+fn write_group<W: Write>(
+    writer: &mut W,
+    kind: u32,
+    changesets: &[object_id],
+    path: &[u8],
+) -> io::Result<()> {
+    let mut buf = strbuf::new();
+    unsafe {
+        dump_changegroup(
+            kind,
+            changesets.as_ptr(),
+            changesets.len(),
+            path.as_ptr(),
+            path.len(),
+            &mut buf,
+        );
+    }
+    writer.write_all(buf.as_bytes())
+}
+
+// The file paths touched by the given changesets, in manifest order.
+fn changed_files(changesets: &[object_id]) -> Vec<Vec<u8>> {
+    let mut buf = strbuf::new();
+    unsafe {
+        changed_file_paths(changesets.as_ptr(), changesets.len(), &mut buf);
+    }
+    buf.as_bytes()
+        .split(|&b| b == b'\0')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_owned())
+        .collect()
+}
+
+/// The changelog heads, as git objects.
+pub fn changeset_heads() -> Vec<object_id> {
+    // Delegates to the C metadata store, which tracks the current set of
+    // changelog heads.
+    extern "C" {
+        fn changeset_head_count() -> usize;
+        fn changeset_head(index: usize, oid: *mut object_id);
+    }
+    let mut heads = Vec::new();
+    unsafe {
+        for i in 0..changeset_head_count() {
+            let mut oid = object_id::null();
+            changeset_head(i, &mut oid);
+            heads.push(oid);
+        }
+    }
+    heads
+}